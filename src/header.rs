@@ -1,4 +1,5 @@
 use crate::util;
+use sha2::{Sha256, Sha384, Sha512};
 use sha3::{Digest, Sha3_256};
 use std::fmt;
 use std::io;
@@ -16,6 +17,10 @@ enum TbfHeaderTypes {
     PackageName = 3,
     PicOption1 = 4,
     FixedAddresses = 5,
+    Permissions = 6,
+    StoragePermissions = 7,
+    KernelVersion = 8,
+    Program = 9,
 }
 
 #[repr(C)]
@@ -45,6 +50,17 @@ struct TbfHeaderMain {
     app_id: u32,
 }
 
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+struct TbfHeaderProgram {
+    base: TbfHeaderTlv,
+    init_fn_offset: u32,
+    protected_trailer_size: u32,
+    minimum_ram_size: u32,
+    binary_end_offset: u32,
+    app_version: u32,
+}
+
 #[repr(C)]
 #[derive(Clone, Copy, Debug)]
 struct TbfHeaderWriteableFlashRegion {
@@ -61,6 +77,29 @@ struct TbfHeaderFixedAddresses {
     start_process_flash: u32,
 }
 
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+struct TbfHeaderKernelVersion {
+    base: TbfHeaderTlv,
+    major: u16,
+    minor: u16,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+struct TbfHeaderDriverPermission {
+    driver_number: u32,
+    offset: u32,
+    allowed_commands: u32,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+struct TbfHeaderStoragePermissions {
+    base: TbfHeaderTlv,
+    write_id: u32,
+}
+
 impl fmt::Display for TbfHeaderBase {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         writeln!(
@@ -89,6 +128,25 @@ impl fmt::Display for TbfHeaderMain {
     }
 }
 
+impl fmt::Display for TbfHeaderProgram {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "
+        init_fn_offset: {0:>9} {0:>#10X}
+protected_trailer_size: {1:>9} {1:>#10X}
+      minimum_ram_size: {2:>9} {2:>#10X}
+      binary_end_offset: {3:>8} {3:>#10X}
+            app_version: {4:>8} {4:>#10X}",
+            self.init_fn_offset,
+            self.protected_trailer_size,
+            self.minimum_ram_size,
+            self.binary_end_offset,
+            self.app_version,
+        )
+    }
+}
+
 impl fmt::Display for TbfHeaderWriteableFlashRegion {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         writeln!(
@@ -114,18 +172,95 @@ impl fmt::Display for TbfHeaderFixedAddresses {
     }
 }
 
+impl fmt::Display for TbfHeaderKernelVersion {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "
+        kernel version:
+                 major: {0:>8} {0:>#10X}
+                 minor: {1:>8} {1:>#10X}",
+            self.major, self.minor,
+        )
+    }
+}
+
+impl fmt::Display for TbfHeaderDriverPermission {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "
+    permission:
+         driver_number: {0:>8} {0:>#10X}
+                offset: {1:>8} {1:>#10X}
+      allowed_commands: {2:>8} {2:>#10X}",
+            self.driver_number, self.offset, self.allowed_commands,
+        )
+    }
+}
+
+impl fmt::Display for TbfHeaderStoragePermissions {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "
+    storage permissions:
+              write_id: {0:>8} {0:>#10X}",
+            self.write_id,
+        )
+    }
+}
+
+// Footers are appended after the application binary (once its
+// `binary_end_offset` is known) rather than living inside the header TLV
+// area, so they get their own small type namespace.
+#[repr(u16)]
+#[derive(Clone, Copy, Debug)]
+#[allow(dead_code)]
+enum TbfFooterTypes {
+    Credentials = 128,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+struct TbfFooterTlv {
+    tipe: TbfFooterTypes,
+    length: u16,
+}
+
+/// Which cryptographic digest a Credentials footer carries.
+#[derive(Clone, Copy, Debug)]
+pub enum TbfFooterCredentialsType {
+    Sha256 = 2,
+    Sha384 = 3,
+    Sha512 = 4,
+}
+
 pub struct TbfHeader {
     hdr_base: TbfHeaderBase,
-    hdr_main: TbfHeaderMain,
+    hdr_main: Option<TbfHeaderMain>,
+    hdr_program: Option<TbfHeaderProgram>,
     hdr_pkg_name_tlv: Option<TbfHeaderTlv>,
     hdr_wfr: Vec<TbfHeaderWriteableFlashRegion>,
     hdr_fixed_addresses: Option<TbfHeaderFixedAddresses>,
+    hdr_kernel_version: Option<TbfHeaderKernelVersion>,
+    hdr_permissions_tlv: Option<TbfHeaderTlv>,
+    hdr_permissions: Vec<TbfHeaderDriverPermission>,
+    hdr_storage_permissions: Option<TbfHeaderStoragePermissions>,
+    storage_read_ids: Vec<u32>,
+    storage_access_ids: Vec<u32>,
+    credentials_format: Option<TbfFooterCredentialsType>,
+    binary_end_offset_locked: bool,
     package_name: String,
     package_name_pad: usize,
 }
 
 impl TbfHeader {
-    pub fn new() -> Self {
+    /// Create a new header. If `program_header` is true, the header will use
+    /// the Program TLV (type 9) in place of the Main TLV (type 1), which
+    /// additionally records where the application binary ends so that
+    /// footers (for example integrity credentials) can follow it.
+    pub fn new(program_header: bool) -> Self {
         Self {
             hdr_base: TbfHeaderBase {
                 version: 2, // Current version is 2.
@@ -134,20 +269,48 @@ impl TbfHeader {
                 flags: 0,
                 checksum: 0,
             },
-            hdr_main: TbfHeaderMain {
-                base: TbfHeaderTlv {
-                    tipe: TbfHeaderTypes::Main,
-                    length: (mem::size_of::<TbfHeaderMain>() - mem::size_of::<TbfHeaderTlv>())
-                        as u16,
-                },
-                init_fn_offset: 0,
-                protected_size: 0,
-                minimum_ram_size: 0,
-                app_id: 0,
+            hdr_main: if program_header {
+                None
+            } else {
+                Some(TbfHeaderMain {
+                    base: TbfHeaderTlv {
+                        tipe: TbfHeaderTypes::Main,
+                        length: (mem::size_of::<TbfHeaderMain>() - mem::size_of::<TbfHeaderTlv>())
+                            as u16,
+                    },
+                    init_fn_offset: 0,
+                    protected_size: 0,
+                    minimum_ram_size: 0,
+                    app_id: 0,
+                })
+            },
+            hdr_program: if program_header {
+                Some(TbfHeaderProgram {
+                    base: TbfHeaderTlv {
+                        tipe: TbfHeaderTypes::Program,
+                        length: (mem::size_of::<TbfHeaderProgram>()
+                            - mem::size_of::<TbfHeaderTlv>()) as u16,
+                    },
+                    init_fn_offset: 0,
+                    protected_trailer_size: 0,
+                    minimum_ram_size: 0,
+                    binary_end_offset: 0,
+                    app_version: 0,
+                })
+            } else {
+                None
             },
             hdr_pkg_name_tlv: None,
             hdr_wfr: Vec::new(),
             hdr_fixed_addresses: None,
+            hdr_kernel_version: None,
+            hdr_permissions_tlv: None,
+            hdr_permissions: Vec::new(),
+            hdr_storage_permissions: None,
+            storage_read_ids: Vec::new(),
+            storage_access_ids: Vec::new(),
+            credentials_format: None,
+            binary_end_offset_locked: false,
             package_name: String::new(),
             package_name_pad: 0,
         }
@@ -160,6 +323,7 @@ impl TbfHeader {
     ///
     /// Returns: The length of the header in bytes. The length is guaranteed
     ///          to be a multiple of 4.
+    #[allow(clippy::too_many_arguments)]
     pub fn create(
         &mut self,
         minimum_ram_size: u32,
@@ -168,13 +332,22 @@ impl TbfHeader {
         fixed_address_ram: Option<u32>,
         fixed_address_flash: Option<u32>,
         app_id: Option<u32>,
+        kernel_version: Option<(u16, u16)>,
+        permissions: Vec<(u32, u32, u32)>,
+        storage_perms: Option<(u32, Vec<u32>, Vec<u32>)>,
+        integrity: Option<TbfFooterCredentialsType>,
     ) -> usize {
         // Create a hasher, this is used for the default AppID
         let mut hasher = Sha3_256::new();
 
         // Need to calculate lengths ahead of time.
-        // Need the base and the main section.
-        let mut header_length = mem::size_of::<TbfHeaderBase>() + mem::size_of::<TbfHeaderMain>();
+        // Need the base and the main (or program) section.
+        let mut header_length = mem::size_of::<TbfHeaderBase>()
+            + if self.hdr_program.is_some() {
+                mem::size_of::<TbfHeaderProgram>()
+            } else {
+                mem::size_of::<TbfHeaderMain>()
+            };
 
         // If we have a package name, add that section.
         self.package_name_pad = if !package_name.is_empty() {
@@ -199,13 +372,45 @@ impl TbfHeader {
             header_length += mem::size_of::<TbfHeaderFixedAddresses>();
         }
 
+        // If a minimum kernel version was given, include the kernel version
+        // header.
+        if kernel_version.is_some() {
+            header_length += mem::size_of::<TbfHeaderKernelVersion>();
+        }
+
+        // If there are permissions, add room for the TLV, the count, the
+        // padding needed to get back to 4-byte alignment after that count,
+        // and the packed permission entries themselves.
+        if !permissions.is_empty() {
+            header_length += mem::size_of::<TbfHeaderTlv>()
+                + mem::size_of::<u16>()
+                + 2
+                + mem::size_of::<TbfHeaderDriverPermission>() * permissions.len();
+        }
+
+        // If storage permissions were given, add room for the fixed part of
+        // the header plus the read and access id lists, each preceded by a
+        // count. The two counts keep the whole section 4-byte aligned.
+        if let Some((_write_id, read_ids, access_ids)) = &storage_perms {
+            header_length += mem::size_of::<TbfHeaderStoragePermissions>()
+                + mem::size_of::<u16>()
+                + mem::size_of::<u32>() * read_ids.len()
+                + mem::size_of::<u16>()
+                + mem::size_of::<u32>() * access_ids.len();
+        }
+
         // Flags default to app is enabled.
         let flags = 0x0000_0001;
 
         // Fill in the fields that we can at this point.
         self.hdr_base.header_size = header_length as u16;
         self.hdr_base.flags = flags;
-        self.hdr_main.minimum_ram_size = minimum_ram_size;
+        if let Some(hdr_main) = &mut self.hdr_main {
+            hdr_main.minimum_ram_size = minimum_ram_size;
+        }
+        if let Some(hdr_program) = &mut self.hdr_program {
+            hdr_program.minimum_ram_size = minimum_ram_size;
+        }
 
         // If a package name exists, keep track of it and add it to the header.
         self.package_name = package_name;
@@ -217,15 +422,18 @@ impl TbfHeader {
             hasher.update(self.package_name.clone());
         }
 
-        // Generate an AppID from the package name
-        if app_id.is_none() {
-            let hash = hasher.finalize();
-            self.hdr_main.app_id = hash[0] as u32
-                | (hash[1] as u32) << 8
-                | (hash[2] as u32) << 16
-                | (hash[3] as u32) << 24;
-        } else {
-            self.hdr_main.app_id = app_id.unwrap();
+        // Generate an AppID from the package name. The Program TLV has no
+        // app_id field (unlike Main), so this only applies there.
+        if let Some(hdr_main) = &mut self.hdr_main {
+            if app_id.is_none() {
+                let hash = hasher.finalize();
+                hdr_main.app_id = hash[0] as u32
+                    | (hash[1] as u32) << 8
+                    | (hash[2] as u32) << 16
+                    | (hash[3] as u32) << 24;
+            } else {
+                hdr_main.app_id = app_id.unwrap();
+            }
         }
 
         // If there is an app state region, start setting up that header.
@@ -252,6 +460,68 @@ impl TbfHeader {
             });
         }
 
+        // If a minimum kernel version was given, fill in the kernel version
+        // header.
+        if let Some((major, minor)) = kernel_version {
+            self.hdr_kernel_version = Some(TbfHeaderKernelVersion {
+                base: TbfHeaderTlv {
+                    tipe: TbfHeaderTypes::KernelVersion,
+                    length: 4,
+                },
+                major,
+                minor,
+            });
+        }
+
+        // If permissions were given, record them and set up the TLV that
+        // precedes them.
+        if !permissions.is_empty() {
+            self.hdr_permissions_tlv = Some(TbfHeaderTlv {
+                tipe: TbfHeaderTypes::Permissions,
+                length: (mem::size_of::<u16>()
+                    + 2
+                    + mem::size_of::<TbfHeaderDriverPermission>() * permissions.len())
+                    as u16,
+            });
+            for (driver_number, offset, allowed_commands) in permissions {
+                self.hdr_permissions.push(TbfHeaderDriverPermission {
+                    driver_number,
+                    offset,
+                    allowed_commands,
+                });
+            }
+        }
+
+        // If storage permissions were given, record them and set up the TLV
+        // that precedes them.
+        if let Some((write_id, read_ids, access_ids)) = storage_perms {
+            self.hdr_storage_permissions = Some(TbfHeaderStoragePermissions {
+                base: TbfHeaderTlv {
+                    tipe: TbfHeaderTypes::StoragePermissions,
+                    length: (mem::size_of::<u32>()
+                        + mem::size_of::<u16>()
+                        + mem::size_of::<u32>() * read_ids.len()
+                        + mem::size_of::<u16>()
+                        + mem::size_of::<u32>() * access_ids.len())
+                        as u16,
+                },
+                write_id,
+            });
+            self.storage_read_ids = read_ids;
+            self.storage_access_ids = access_ids;
+        }
+
+        // Record which digest, if any, a Credentials footer should use. This
+        // does not affect the header itself; it is consulted later by
+        // `append_credentials_footer` once the application binary exists. A
+        // Credentials footer locates itself via binary_end_offset, which only
+        // the Program header defines, so requesting one without a Program
+        // header doesn't make sense -- but `create` returns a plain `usize`
+        // and has no way to surface that as an error, so we just record it
+        // here and let `append_credentials_footer` reject the mismatch with
+        // a proper `Err` when it's actually used.
+        self.credentials_format = integrity;
+
         // Return the length by generating the header and seeing how long it is.
         self.generate()
             .expect("No header was generated")
@@ -263,17 +533,51 @@ impl TbfHeader {
     /// not include the size of the header itself (as defined in the Main TLV
     /// element type).
     pub fn set_protected_size(&mut self, protected_size: u32) {
-        self.hdr_main.protected_size = protected_size;
+        if let Some(hdr_main) = &mut self.hdr_main {
+            hdr_main.protected_size = protected_size;
+        }
+        if let Some(hdr_program) = &mut self.hdr_program {
+            hdr_program.protected_trailer_size = protected_size;
+        }
     }
 
-    /// Update the header with correct size for the entire app binary.
+    /// Update the header with correct size for the entire app binary. Until
+    /// `finalize_binary_end_offset` has been called, this also tracks
+    /// `binary_end_offset` (Program header) as the current end of the
+    /// covered application binary. Once footers exist, call
+    /// `finalize_binary_end_offset` first and then grow `total_size` to
+    /// cover them without disturbing `binary_end_offset`.
     pub fn set_total_size(&mut self, total_size: u32) {
         self.hdr_base.total_size = total_size;
+        if !self.binary_end_offset_locked {
+            if let Some(hdr_program) = &mut self.hdr_program {
+                hdr_program.binary_end_offset = total_size;
+            }
+        }
     }
 
     /// Update the header with the correct offset for the _start function.
     pub fn set_init_fn_offset(&mut self, init_fn_offset: u32) {
-        self.hdr_main.init_fn_offset = init_fn_offset;
+        if let Some(hdr_main) = &mut self.hdr_main {
+            hdr_main.init_fn_offset = init_fn_offset;
+        }
+        if let Some(hdr_program) = &mut self.hdr_program {
+            hdr_program.init_fn_offset = init_fn_offset;
+            if !self.binary_end_offset_locked {
+                hdr_program.binary_end_offset = self.hdr_base.total_size;
+            }
+        }
+    }
+
+    /// Lock in the current `binary_end_offset` (Program header) as the
+    /// boundary between the application binary and any footers appended
+    /// after it. Call this once the app binary itself is fully assembled,
+    /// before calling `set_total_size` again to grow `total_size` over a
+    /// footer returned by `append_credentials_footer` — otherwise
+    /// `set_total_size` would move `binary_end_offset` along with it and the
+    /// loader would have no way to find where the footers begin.
+    pub fn finalize_binary_end_offset(&mut self) {
+        self.binary_end_offset_locked = true;
     }
 
     /// Update the header with appstate values if appropriate.
@@ -294,7 +598,11 @@ impl TbfHeader {
 
         // Write all bytes to an in-memory file for the header.
         header_buf.write_all(unsafe { util::as_byte_slice(&self.hdr_base) })?;
-        header_buf.write_all(unsafe { util::as_byte_slice(&self.hdr_main) })?;
+        if self.hdr_program.is_some() {
+            header_buf.write_all(unsafe { util::as_byte_slice(&self.hdr_program) })?;
+        } else {
+            header_buf.write_all(unsafe { util::as_byte_slice(&self.hdr_main) })?;
+        }
         if !self.package_name.is_empty() {
             header_buf.write_all(unsafe { util::as_byte_slice(&self.hdr_pkg_name_tlv) })?;
             header_buf.write_all(self.package_name.as_ref())?;
@@ -311,13 +619,53 @@ impl TbfHeader {
             header_buf.write_all(unsafe { util::as_byte_slice(&self.hdr_fixed_addresses) })?;
         }
 
+        // If there is a minimum kernel version, include that TLV.
+        if self.hdr_kernel_version.is_some() {
+            header_buf.write_all(unsafe { util::as_byte_slice(&self.hdr_kernel_version) })?;
+        }
+
+        // If there are permissions, include the TLV, the count of entries,
+        // the padding needed to realign to 4 bytes, and the entries.
+        if let Some(permissions_tlv) = &self.hdr_permissions_tlv {
+            header_buf.write_all(unsafe { util::as_byte_slice(permissions_tlv) })?;
+            header_buf.write_all(&(self.hdr_permissions.len() as u16).to_le_bytes())?;
+            util::do_pad(&mut header_buf, 2)?;
+            for permission in &self.hdr_permissions {
+                header_buf.write_all(unsafe { util::as_byte_slice(permission) })?;
+            }
+        }
+
+        // If there are storage permissions, include the TLV, the write id,
+        // the read id list (with its count), and the access id list (with
+        // its count).
+        if let Some(storage_permissions) = &self.hdr_storage_permissions {
+            header_buf.write_all(unsafe { util::as_byte_slice(storage_permissions) })?;
+            header_buf.write_all(&(self.storage_read_ids.len() as u16).to_le_bytes())?;
+            for read_id in &self.storage_read_ids {
+                header_buf.write_all(&read_id.to_le_bytes())?;
+            }
+            header_buf.write_all(&(self.storage_access_ids.len() as u16).to_le_bytes())?;
+            for access_id in &self.storage_access_ids {
+                header_buf.write_all(&access_id.to_le_bytes())?;
+            }
+        }
+
         let current_length = header_buf.get_ref().len();
         util::do_pad(
             &mut header_buf,
             amount_alignment_needed(current_length as u32, 4) as usize,
         )?;
 
-        self.inject_checksum(header_buf)
+        let header_buf = self.inject_checksum(header_buf)?;
+
+        // Catch TLV-padding and offset-arithmetic bugs as soon as they are
+        // introduced by re-reading the header the way a loader would.
+        #[cfg(debug_assertions)]
+        if let Err(e) = self.verify(header_buf.get_ref()) {
+            panic!("generated TBF header failed self-verification: {}", e);
+        }
+
+        Ok(header_buf)
     }
 
     /// Take a TBF header and calculate the checksum. Then insert that checksum
@@ -356,18 +704,350 @@ impl TbfHeader {
 
         Ok(header_buf)
     }
+
+    /// Build a Credentials footer (type 128) covering `binary`, which must be
+    /// the fully assembled TBF (header plus padded application binary, up to
+    /// `binary_end_offset`). The digest algorithm is the one passed to
+    /// `create` as `integrity`. Returns the footer bytes, already padded to a
+    /// 4-byte boundary; the caller is responsible for appending them after
+    /// `binary` and growing `total_size` by their length.
+    pub fn append_credentials_footer(&self, binary: &[u8]) -> io::Result<Vec<u8>> {
+        let hdr_program = self.hdr_program.as_ref().ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Credentials footers require a Program header (TbfHeader::new(true)); \
+                 binary_end_offset is not defined with a Main header",
+            )
+        })?;
+
+        // The digest must cover exactly the bytes the Program header says the
+        // application binary occupies, or a loader re-verifying it would be
+        // checking a different boundary than whoever built this TBF did.
+        if binary.len() as u32 != hdr_program.binary_end_offset {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "binary is {} bytes but binary_end_offset is {}; \
+                     pass exactly the header plus padded app binary",
+                    binary.len(),
+                    hdr_program.binary_end_offset
+                ),
+            ));
+        }
+
+        let format = self.credentials_format.ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "No integrity format was set on this header; pass `integrity` to create()",
+            )
+        })?;
+
+        let (format_id, data) = match format {
+            TbfFooterCredentialsType::Sha256 => (
+                TbfFooterCredentialsType::Sha256 as u32,
+                Sha256::digest(binary).to_vec(),
+            ),
+            TbfFooterCredentialsType::Sha384 => (
+                TbfFooterCredentialsType::Sha384 as u32,
+                Sha384::digest(binary).to_vec(),
+            ),
+            TbfFooterCredentialsType::Sha512 => (
+                TbfFooterCredentialsType::Sha512 as u32,
+                Sha512::digest(binary).to_vec(),
+            ),
+        };
+
+        let mut footer_buf = io::Cursor::new(Vec::new());
+        let base = TbfFooterTlv {
+            tipe: TbfFooterTypes::Credentials,
+            length: (mem::size_of::<u32>() + data.len()) as u16,
+        };
+        footer_buf.write_all(unsafe { util::as_byte_slice(&base) })?;
+        footer_buf.write_all(&format_id.to_le_bytes())?;
+        footer_buf.write_all(&data)?;
+
+        let current_length = footer_buf.get_ref().len();
+        util::do_pad(
+            &mut footer_buf,
+            amount_alignment_needed(current_length as u32, 4) as usize,
+        )?;
+
+        Ok(footer_buf.into_inner())
+    }
+
+    /// Re-read a generated header the way a loader would and check that it
+    /// is internally consistent: the base fields are sane, the XOR-word
+    /// checksum matches, and every TLV stays within `header_size`, starts
+    /// 4-byte aligned, and (once `total_size` has been finalized) points
+    /// inside the app binary. Returns `Err` describing the first problem
+    /// found rather than panicking, so callers can decide how to react.
+    pub fn verify(&self, bytes: &[u8]) -> Result<(), String> {
+        if bytes.len() < mem::size_of::<TbfHeaderBase>() {
+            return Err("buffer is shorter than the base header".to_string());
+        }
+
+        let version = u16::from_le_bytes([bytes[0], bytes[1]]);
+        let header_size = u16::from_le_bytes([bytes[2], bytes[3]]) as usize;
+        let total_size = u32::from_le_bytes([bytes[4], bytes[5], bytes[6], bytes[7]]);
+        let stored_checksum = u32::from_le_bytes([bytes[12], bytes[13], bytes[14], bytes[15]]);
+
+        if version != 2 {
+            return Err(format!("unexpected header version {}", version));
+        }
+        if header_size > bytes.len() {
+            return Err(format!(
+                "header_size {} is larger than the {} bytes available",
+                header_size,
+                bytes.len()
+            ));
+        }
+        // total_size is only meaningful once the caller has finished
+        // assembling the full TBF; a header generated mid-`create` still has
+        // it set to zero.
+        if total_size != 0 && (total_size as usize) < header_size {
+            return Err(format!(
+                "total_size {} is smaller than header_size {}",
+                total_size, header_size
+            ));
+        }
+
+        // Recompute the checksum exactly as `inject_checksum` does: XOR all
+        // header words together, treating the checksum's own word as zero.
+        let mut checksum: u32 = 0;
+        let mut offset = 0;
+        while offset < header_size {
+            let count = (header_size - offset).min(4);
+            let mut wordbuf = [0_u8; 4];
+            wordbuf[..count].copy_from_slice(&bytes[offset..offset + count]);
+            if offset == 12 {
+                wordbuf = [0; 4];
+            }
+            checksum ^= u32::from_le_bytes(wordbuf);
+            offset += 4;
+        }
+        if checksum != stored_checksum {
+            return Err(format!(
+                "checksum mismatch: computed {:#010X}, stored {:#010X}",
+                checksum, stored_checksum
+            ));
+        }
+
+        // Walk the TLV entries following the base header.
+        let mut offset = mem::size_of::<TbfHeaderBase>();
+        let mut init_fn_offset = None;
+        let mut wfr_entries = Vec::new();
+        let mut binary_end_offset = None;
+        while offset < header_size {
+            if offset % 4 != 0 {
+                return Err(format!("TLV at offset {} is not 4-byte aligned", offset));
+            }
+            if offset + 4 > header_size {
+                return Err(format!("truncated TLV header at offset {}", offset));
+            }
+            let tipe = u16::from_le_bytes([bytes[offset], bytes[offset + 1]]);
+            let length = u16::from_le_bytes([bytes[offset + 2], bytes[offset + 3]]) as usize;
+            let body_start = offset + 4;
+            if body_start + length > header_size {
+                return Err(format!(
+                    "TLV type {} at offset {} overruns header_size {}",
+                    tipe, offset, header_size
+                ));
+            }
+
+            // Main and Program both start with a u32 init_fn_offset;
+            // WriteableFlashRegions starts with a u32 offset.
+            if (tipe == TbfHeaderTypes::Main as u16 || tipe == TbfHeaderTypes::Program as u16)
+                && length >= mem::size_of::<u32>()
+            {
+                init_fn_offset = Some(u32::from_le_bytes(
+                    bytes[body_start..body_start + 4].try_into().unwrap(),
+                ));
+            }
+            // WriteableFlashRegions is { offset: u32, size: u32 }. A region
+            // with size 0 is an unused slot (see
+            // `set_writeable_flash_region_values`), not a real region at
+            // flash offset 0, so track size alongside offset rather than
+            // using offset == 0 as the "unused" sentinel.
+            if tipe == TbfHeaderTypes::WriteableFlashRegions as u16 && length >= 8 {
+                let wfr_offset =
+                    u32::from_le_bytes(bytes[body_start..body_start + 4].try_into().unwrap());
+                let wfr_size = u32::from_le_bytes(
+                    bytes[body_start + 4..body_start + 8].try_into().unwrap(),
+                );
+                wfr_entries.push((wfr_offset, wfr_size));
+            }
+            // Program's fields are init_fn_offset, protected_trailer_size,
+            // minimum_ram_size, binary_end_offset, app_version, in that
+            // order, so binary_end_offset sits at body offset 12.
+            if tipe == TbfHeaderTypes::Program as u16 && length >= 16 {
+                binary_end_offset = Some(u32::from_le_bytes(
+                    bytes[body_start + 12..body_start + 16].try_into().unwrap(),
+                ));
+            }
+
+            // PackageName's length is the raw string length, which is not
+            // necessarily a multiple of 4; `create` pads the body out to the
+            // next 4-byte boundary without reflecting that in `length`, so
+            // do the same padding here when locating the next TLV.
+            offset = body_start + length + amount_alignment_needed(length as u32, 4) as usize;
+        }
+
+        if total_size != 0 {
+            if let Some(init_fn_offset) = init_fn_offset {
+                if init_fn_offset >= total_size {
+                    return Err(format!(
+                        "init_fn_offset {} falls outside total_size {}",
+                        init_fn_offset, total_size
+                    ));
+                }
+            }
+            for (wfr_offset, wfr_size) in wfr_entries {
+                // size == 0 means the slot hasn't been filled in yet (see
+                // `set_writeable_flash_region_values`); offset == 0 is a
+                // perfectly valid region start and must still be checked.
+                if wfr_size != 0 && wfr_offset >= total_size {
+                    return Err(format!(
+                        "writeable flash region offset {} falls outside total_size {}",
+                        wfr_offset, total_size
+                    ));
+                }
+            }
+            // binary_end_offset marks where the application binary ends and
+            // any footers begin, so it must not exceed total_size (which
+            // covers both); it may be strictly less once footers exist.
+            if let Some(binary_end_offset) = binary_end_offset {
+                if binary_end_offset > total_size {
+                    return Err(format!(
+                        "binary_end_offset {} falls outside total_size {}",
+                        binary_end_offset, total_size
+                    ));
+                }
+            }
+        }
+
+        Ok(())
+    }
 }
 
 impl fmt::Display for TbfHeader {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(f, "TBF Header:")?;
         write!(f, "{}", self.hdr_base)?;
-        write!(f, "{}", self.hdr_main)?;
+        if let Some(hdr_main) = &self.hdr_main {
+            write!(f, "{}", hdr_main)?;
+        }
+        if let Some(hdr_program) = &self.hdr_program {
+            write!(f, "{}", hdr_program)?;
+        }
         for wfr in &self.hdr_wfr {
             write!(f, "{}", wfr)?;
         }
         self.hdr_fixed_addresses
             .map_or(Ok(()), |hdr| write!(f, "{}", hdr))?;
+        self.hdr_kernel_version
+            .map_or(Ok(()), |hdr| write!(f, "{}", hdr))?;
+        for permission in &self.hdr_permissions {
+            write!(f, "{}", permission)?;
+        }
+        if let Some(storage_permissions) = &self.hdr_storage_permissions {
+            write!(f, "{}", storage_permissions)?;
+            writeln!(f, "              read_ids: {:?}", self.storage_read_ids)?;
+            writeln!(f, "            access_ids: {:?}", self.storage_access_ids)?;
+        }
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn valid_header_bytes() -> Vec<u8> {
+        let mut hdr = TbfHeader::new(false);
+        let len = hdr.create(
+            2048,
+            0,
+            "app".to_string(),
+            None,
+            None,
+            None,
+            Some((2, 0)),
+            vec![],
+            None,
+            None,
+        );
+        hdr.set_total_size(len as u32 + 100);
+        hdr.set_init_fn_offset(len as u32 + 4);
+        let bytes = hdr.generate().unwrap().into_inner();
+        assert_eq!(bytes.len(), len);
+        bytes
+    }
+
+    // Recompute and rewrite the checksum the same way `inject_checksum`
+    // does, so a test can corrupt one invariant (alignment, an offset) in
+    // isolation without also tripping the (unrelated) checksum check.
+    fn fixup_checksum(bytes: &mut [u8]) {
+        let header_size = u16::from_le_bytes([bytes[2], bytes[3]]) as usize;
+        let mut checksum: u32 = 0;
+        let mut offset = 0;
+        while offset < header_size {
+            let count = (header_size - offset).min(4);
+            let mut wordbuf = [0_u8; 4];
+            wordbuf[..count].copy_from_slice(&bytes[offset..offset + count]);
+            if offset == 12 {
+                wordbuf = [0; 4];
+            }
+            checksum ^= u32::from_le_bytes(wordbuf);
+            offset += 4;
+        }
+        bytes[12..16].copy_from_slice(&checksum.to_le_bytes());
+    }
+
+    #[test]
+    fn verify_accepts_an_unmodified_header() {
+        let bytes = valid_header_bytes();
+        let hdr = TbfHeader::new(false);
+        assert!(hdr.verify(&bytes).is_ok());
+    }
+
+    #[test]
+    fn verify_rejects_a_corrupted_checksum() {
+        let mut bytes = valid_header_bytes();
+        bytes[12] ^= 0xFF;
+        let hdr = TbfHeader::new(false);
+        let err = hdr.verify(&bytes).expect_err("corrupted checksum should be rejected");
+        assert!(err.contains("checksum mismatch"), "{}", err);
+    }
+
+    #[test]
+    fn verify_rejects_a_tlv_that_overruns_the_header() {
+        let mut bytes = valid_header_bytes();
+        // The Main TLV's length field sits right after its tipe field, at
+        // header offset 16 (base) + 2 (tipe) = 18. Inflating it makes the
+        // TLV's body run past header_size.
+        let corrupted_length = u16::from_le_bytes([bytes[18], bytes[19]]) + 0x1000;
+        bytes[18..20].copy_from_slice(&corrupted_length.to_le_bytes());
+        fixup_checksum(&mut bytes);
+        let hdr = TbfHeader::new(false);
+        let err = hdr
+            .verify(&bytes)
+            .expect_err("a TLV overrunning header_size should be rejected");
+        assert!(err.contains("overruns header_size"), "{}", err);
+    }
+
+    #[test]
+    fn verify_rejects_an_out_of_range_init_fn_offset() {
+        let mut bytes = valid_header_bytes();
+        let total_size = u32::from_le_bytes(bytes[4..8].try_into().unwrap());
+        // The Main TLV's body starts right after its own TLV header, at
+        // header offset 16 (base) + 4 (TLV header) = 20; init_fn_offset is
+        // its first field.
+        bytes[20..24].copy_from_slice(&total_size.to_le_bytes());
+        fixup_checksum(&mut bytes);
+        let hdr = TbfHeader::new(false);
+        let err = hdr
+            .verify(&bytes)
+            .expect_err("out-of-range init_fn_offset should be rejected");
+        assert!(err.contains("falls outside total_size"), "{}", err);
+    }
+}